@@ -42,6 +42,7 @@ extern crate rand;
 extern crate rand_distr;
 extern crate regex;
 extern crate remoteprocess;
+extern crate serde_json;
 #[cfg(windows)]
 extern crate winapi;
 
@@ -49,6 +50,7 @@ pub mod binary_parser;
 pub mod config;
 #[cfg(unwind)]
 mod cython;
+mod minidump;
 #[cfg(unwind)]
 mod native_stack_trace;
 mod python_bindings;
@@ -70,18 +72,18 @@ pub use stack_trace::Frame;
 pub use stack_trace::StackTrace;
 
 use crate::config::LockingStrategy;
-use std::collections::HashMap;
+use libc::c_void;
 use std::slice;
-use std::sync::Mutex;
 
 use rand::thread_rng;
 use rand::seq::SliceRandom;
 
-lazy_static! {
-    static ref HASHMAP: Mutex<HashMap<Pid, Sampler>> = {
-        let h = HashMap::new();
-        Mutex::new(h)
-    };
+/// Opaque handle returned by `pyspy_init`, wrapping a `Sampler`. Each handle
+/// owns its sampler independently, so a host program can run several
+/// samplers with distinct configs concurrently instead of serializing on a
+/// single shared lock.
+pub struct PySpyHandle {
+    sampler: Sampler,
 }
 
 fn copy_error(err_ptr: *mut u8, err_len: i32, err_str: String) -> i32 {
@@ -95,84 +97,197 @@ fn copy_error(err_ptr: *mut u8, err_len: i32, err_str: String) -> i32 {
     -(l as i32)
 }
 
+/// Creates a sampler for `pid` and returns it as an opaque handle that the
+/// caller must pass to `pyspy_snapshot`/`pyspy_write_minidump`/`pyspy_cleanup`.
+/// Returns a null pointer and writes the error message on failure.
 #[no_mangle]
-pub extern "C" fn pyspy_init(pid: Pid, blocking: i32, err_ptr: *mut u8, err_len: i32) -> i32 {
+pub extern "C" fn pyspy_init(
+    pid: Pid,
+    blocking: i32,
+    err_ptr: *mut u8,
+    err_len: i32,
+) -> *mut PySpyHandle {
     let mut config = config::Config::default();
     if blocking == 0 {
         config.blocking = LockingStrategy::NonBlocking;
     }
     match Sampler::new(pid, &config) {
-        Ok(sampler) => {
-            let mut map = HASHMAP.lock().unwrap(); // get()
-            map.insert(pid, sampler);
-            1
+        Ok(sampler) => Box::into_raw(Box::new(PySpyHandle { sampler })),
+        Err(err) => {
+            copy_error(err_ptr, err_len, err.to_string());
+            std::ptr::null_mut()
         }
-        Err(err) => copy_error(err_ptr, err_len, err.to_string()),
     }
 }
 
+/// Frees a handle returned by `pyspy_init`. The handle must not be used
+/// again after this call.
 #[no_mangle]
-pub extern "C" fn pyspy_cleanup(pid: Pid, err_ptr: *mut u8, err_len: i32) -> i32 {
-    let mut map = HASHMAP.lock().unwrap(); // get()
-    map.remove(&pid);
+pub extern "C" fn pyspy_cleanup(
+    handle: *mut PySpyHandle,
+    err_ptr: *mut u8,
+    err_len: i32,
+) -> i32 {
+    if handle.is_null() {
+        return copy_error(err_ptr, err_len, "handle is null".to_string());
+    }
+    unsafe {
+        drop(Box::from_raw(handle));
+    }
     1
 }
 
 #[no_mangle]
 pub extern "C" fn pyspy_snapshot(
-    pid: Pid,
+    handle: *mut PySpyHandle,
     ptr: *mut u8,
     len: i32,
     err_ptr: *mut u8,
     err_len: i32,
 ) -> i32 {
-    let mut map = HASHMAP.lock().unwrap(); // get()
-    match map.get_mut(&pid) {
-        Some(sampler) => {
-            for sample in sampler {
-                let mut string_list = vec![];
-                let mut traces: Vec<StackTrace> = sample.traces;
-                traces.shuffle(&mut thread_rng());
-
-                for thread in traces.iter() {
-                    if !thread.active {
-                        continue;
-                    }
-                    for frame in &thread.frames {
-                        let filename = match &frame.short_filename {
-                            Some(f) => &f,
-                            None => &frame.filename,
-                        };
-                        if frame.line != 0 {
-                            string_list
-                                .insert(0, format!("{}:{} - {}", filename, frame.line, frame.name));
-                        } else {
-                            string_list.insert(0, format!("{} - {}", filename, frame.name));
-                        }
-                    }
-                    break;
-                }
-                let joined = string_list.join(";");
-                let joined_slice = joined.as_bytes();
-                let l = joined_slice.len();
-
-                if len < (l as i32) {
-                    // println!("buffer is too small");
-                    // io::stdout().flush().unwrap();
-                    return copy_error(err_ptr, err_len, "buffer is too small".to_string());
-                } else {
-                    let slice = unsafe { slice::from_raw_parts_mut(ptr, l as usize) };
-                    slice.clone_from_slice(joined_slice);
-                    return l as i32;
-                }
+    if handle.is_null() {
+        return copy_error(err_ptr, err_len, "handle is null".to_string());
+    }
+    let handle = unsafe { &mut *handle };
+
+    for sample in &mut handle.sampler {
+        let mut traces: Vec<StackTrace> = sample.traces;
+        traces.shuffle(&mut thread_rng());
+
+        let mut joined = String::new();
+        for thread in traces.iter() {
+            if !thread.active {
+                continue;
             }
+            joined = minidump::format_stack(thread);
+            break;
+        }
+        let joined_slice = joined.as_bytes();
+        let l = joined_slice.len();
 
-            return 0;
+        if len < (l as i32) {
+            return copy_error(err_ptr, err_len, "buffer is too small".to_string());
+        } else {
+            let slice = unsafe { slice::from_raw_parts_mut(ptr, l as usize) };
+            slice.clone_from_slice(joined_slice);
+            return l as i32;
         }
-        None => copy_error(
-            err_ptr,
-            err_len,
-            "could not find spy for this pid".to_string(),
-        ),
     }
+
+    0
+}
+
+/// Like `pyspy_snapshot`, but serializes the full `Vec<StackTrace>` as JSON
+/// instead of folding it down to one randomly chosen active thread. This carries every
+/// thread's `thread_id`/`active`/`owns_gil`/`os_thread_id` plus each frame's
+/// `filename`/`short_filename`/`module`/`line`/`name`, so consumers that need
+/// per-thread state (e.g. which thread holds the GIL) can get it directly
+/// instead of re-deriving it from a folded stack string.
+#[no_mangle]
+pub extern "C" fn pyspy_snapshot_json(
+    handle: *mut PySpyHandle,
+    ptr: *mut u8,
+    len: i32,
+    err_ptr: *mut u8,
+    err_len: i32,
+) -> i32 {
+    if handle.is_null() {
+        return copy_error(err_ptr, err_len, "handle is null".to_string());
+    }
+    let handle = unsafe { &mut *handle };
+
+    for sample in &mut handle.sampler {
+        let json = match serde_json::to_string(&sample.traces) {
+            Ok(json) => json,
+            Err(err) => return copy_error(err_ptr, err_len, err.to_string()),
+        };
+        let json_bytes = json.as_bytes();
+        let l = json_bytes.len();
+
+        if len < (l as i32) {
+            return copy_error(err_ptr, err_len, "buffer is too small".to_string());
+        } else {
+            let slice = unsafe { slice::from_raw_parts_mut(ptr, l) };
+            slice.clone_from_slice(json_bytes);
+            return l as i32;
+        }
+    }
+
+    0
+}
+
+/// Like `pyspy_snapshot`, but streams one folded stack per active thread to
+/// `callback` instead of writing into a caller-sized buffer. This avoids the
+/// buffer-too-small retry loop entirely: arbitrarily large snapshots flow out
+/// one thread at a time, so the host never has to guess a size up front and
+/// can assemble the result in its own language's allocator. Returns 1 on
+/// success, 0 if there was no sample available, or -1 if `handle` is null.
+///
+/// `callback` is invoked synchronously, once per active thread, with a
+/// pointer/length into a buffer owned by this function that is freed as soon
+/// as the call returns. The callback must copy the bytes it needs before
+/// returning — the pointer is not valid afterward and must not be retained.
+#[no_mangle]
+pub extern "C" fn pyspy_snapshot_stream(
+    handle: *mut PySpyHandle,
+    callback: extern "C" fn(*const u8, usize, *mut c_void),
+    user_data: *mut c_void,
+) -> i32 {
+    if handle.is_null() {
+        return -1;
+    }
+    let handle = unsafe { &mut *handle };
+
+    for sample in &mut handle.sampler {
+        for thread in sample.traces.iter() {
+            if !thread.active {
+                continue;
+            }
+
+            let joined = minidump::format_stack(thread);
+            let joined_slice = joined.as_bytes();
+            callback(joined_slice.as_ptr(), joined_slice.len(), user_data);
+        }
+        return 1;
+    }
+
+    0
+}
+
+/// Captures every thread of the process behind `handle` and writes a
+/// minidump-format snapshot to `path`, so the stack state can be inspected
+/// offline without the process staying alive. Returns the number of bytes
+/// written on success, or the negative-length error convention used
+/// elsewhere in this API.
+#[no_mangle]
+pub extern "C" fn pyspy_write_minidump(
+    handle: *mut PySpyHandle,
+    path_ptr: *const u8,
+    path_len: i32,
+    err_ptr: *mut u8,
+    err_len: i32,
+) -> i32 {
+    if handle.is_null() {
+        return copy_error(err_ptr, err_len, "handle is null".to_string());
+    }
+    if path_len < 0 {
+        return copy_error(err_ptr, err_len, "path_len is negative".to_string());
+    }
+    let handle = unsafe { &mut *handle };
+
+    let path_bytes = unsafe { slice::from_raw_parts(path_ptr, path_len as usize) };
+    let path = match std::str::from_utf8(path_bytes) {
+        Ok(path) => path,
+        Err(_) => return copy_error(err_ptr, err_len, "path is not valid utf-8".to_string()),
+    };
+
+    for sample in &mut handle.sampler {
+        let dump = minidump::write(&sample.traces);
+        return match std::fs::write(path, &dump) {
+            Ok(()) => dump.len() as i32,
+            Err(err) => copy_error(err_ptr, err_len, err.to_string()),
+        };
+    }
+
+    copy_error(err_ptr, err_len, "no samples available".to_string())
 }
@@ -0,0 +1,272 @@
+//! Minidump-format serialization of py-spy snapshots.
+//!
+//! This builds a minimal minidump-compatible container (header + stream
+//! directory) so a captured snapshot can be reopened offline by standard
+//! minidump readers for post-mortem inspection, without requiring the
+//! profiled process to still be alive. Alongside the standard
+//! `ThreadListStream`, it writes a custom "python stacks" stream carrying
+//! the resolved `StackTrace`/`Frame` data gathered during the snapshot.
+
+use crate::stack_trace::StackTrace;
+
+const MINIDUMP_SIGNATURE: u32 = 0x504d_444d; // "MDMP"
+const MINIDUMP_VERSION: u32 = 0xa793;
+
+// Standard minidump stream type for per-thread state.
+const STREAM_THREAD_LIST: u32 = 3;
+// Stream type for the resolved python stack data. Picked outside the range
+// reserved by the minidump format for official stream types.
+const STREAM_PYTHON_STACKS: u32 = 0x5079_5374; // "PySt"
+
+const HEADER_SIZE: u32 = 32;
+const DIRECTORY_ENTRY_SIZE: u32 = 12;
+// sizeof(MINIDUMP_THREAD): ThreadId, SuspendCount, PriorityClass, Priority
+// (4 bytes each), Teb (u64), Stack (MINIDUMP_MEMORY_DESCRIPTOR: u64 +
+// MINIDUMP_LOCATION_DESCRIPTOR), ThreadContext (MINIDUMP_LOCATION_DESCRIPTOR).
+const THREAD_ENTRY_SIZE: u32 = 48;
+const THREAD_CONTEXT_SIZE: u32 = 16 * 8;
+
+struct Directory {
+    stream_type: u32,
+    data_size: u32,
+    rva: u32,
+}
+
+/// In-memory mirror of the real `MINIDUMP_THREAD` record (48 bytes), kept
+/// alongside the separately-stored stack/context byte ranges it points to.
+struct MinidumpThread {
+    thread_id: u32,
+    suspend_count: u32,
+    priority_class: u32,
+    priority: u32,
+    teb: u64,
+    stack_start: u64,
+    stack_size: u32,
+    stack_rva: u32,
+    context_size: u32,
+    context_rva: u32,
+}
+
+/// Serializes a full set of per-thread stack traces into a minidump-compatible
+/// byte buffer: a header, a stream directory, a `ThreadListStream` laid out as
+/// real `MINIDUMP_THREAD`/`MINIDUMP_MEMORY_DESCRIPTOR` records (so standard
+/// minidump readers can parse it), and a custom "python stacks" stream
+/// holding the resolved frame data.
+pub fn write(traces: &[StackTrace]) -> Vec<u8> {
+    let mut buf = vec![0u8; HEADER_SIZE as usize];
+
+    let thread_list_rva = buf.len() as u32;
+    let thread_array_size = 4 + THREAD_ENTRY_SIZE * traces.len() as u32;
+    let aux_base_rva = thread_list_rva + thread_array_size;
+
+    let mut thread_entries = Vec::with_capacity(traces.len());
+    let mut aux = Vec::new();
+
+    for trace in traces {
+        let stack_bytes = format_stack(trace).into_bytes();
+        let stack_rva = aux_base_rva + aux.len() as u32;
+        let stack_size = stack_bytes.len() as u32;
+        aux.extend_from_slice(&stack_bytes);
+
+        // Real register values aren't available once traces have been
+        // resolved to python frames, so the context is a zeroed placeholder
+        // sized like a generic register file.
+        let context_rva = aux_base_rva + aux.len() as u32;
+        aux.extend_from_slice(&[0u8; THREAD_CONTEXT_SIZE as usize]);
+
+        thread_entries.push(MinidumpThread {
+            thread_id: trace.os_thread_id as u32,
+            suspend_count: 0,
+            priority_class: 0,
+            priority: 0,
+            teb: 0,
+            // The real stack's virtual address in the target process isn't
+            // captured anywhere in a resolved StackTrace, so this is left as
+            // an unknown/placeholder, same as `teb` above — NOT the rva of
+            // the stack bytes stored in this file, which is a different
+            // address space entirely.
+            stack_start: 0,
+            stack_size,
+            stack_rva,
+            context_size: THREAD_CONTEXT_SIZE,
+            context_rva,
+        });
+    }
+
+    push_u32(&mut buf, traces.len() as u32);
+    for t in &thread_entries {
+        push_u32(&mut buf, t.thread_id);
+        push_u32(&mut buf, t.suspend_count);
+        push_u32(&mut buf, t.priority_class);
+        push_u32(&mut buf, t.priority);
+        buf.extend_from_slice(&t.teb.to_le_bytes());
+        buf.extend_from_slice(&t.stack_start.to_le_bytes());
+        push_u32(&mut buf, t.stack_size);
+        push_u32(&mut buf, t.stack_rva);
+        push_u32(&mut buf, t.context_size);
+        push_u32(&mut buf, t.context_rva);
+    }
+    buf.extend_from_slice(&aux);
+
+    let python_stacks_rva = buf.len() as u32;
+    let python_stacks = serialize_python_stacks(traces);
+    buf.extend_from_slice(&python_stacks);
+
+    let directory_rva = buf.len() as u32;
+    let directories = [
+        Directory {
+            stream_type: STREAM_THREAD_LIST,
+            data_size: thread_array_size + aux.len() as u32,
+            rva: thread_list_rva,
+        },
+        Directory {
+            stream_type: STREAM_PYTHON_STACKS,
+            data_size: python_stacks.len() as u32,
+            rva: python_stacks_rva,
+        },
+    ];
+    for d in &directories {
+        push_u32(&mut buf, d.stream_type);
+        push_u32(&mut buf, d.data_size);
+        push_u32(&mut buf, d.rva);
+    }
+    debug_assert_eq!(
+        buf.len() as u32,
+        directory_rva + directories.len() as u32 * DIRECTORY_ENTRY_SIZE
+    );
+
+    write_u32(&mut buf, 0, MINIDUMP_SIGNATURE);
+    write_u32(&mut buf, 4, MINIDUMP_VERSION);
+    write_u32(&mut buf, 8, directories.len() as u32);
+    write_u32(&mut buf, 12, directory_rva);
+    write_u32(&mut buf, 16, 0); // checksum, unused
+    write_u32(&mut buf, 20, 0); // time_date_stamp, unused
+    buf[24..32].copy_from_slice(&0u64.to_le_bytes()); // flags, unused
+
+    buf
+}
+
+/// Folds a single thread's frames into the `"file:line - name"` / `"file -
+/// name"` semicolon-joined format shared by the folded-stack FFI entry points,
+/// in the reverse of `trace.frames` order, i.e. outermost frame first.
+pub(crate) fn format_stack(trace: &StackTrace) -> String {
+    let mut frames = Vec::with_capacity(trace.frames.len());
+    for frame in &trace.frames {
+        let filename = match &frame.short_filename {
+            Some(f) => f,
+            None => &frame.filename,
+        };
+        if frame.line != 0 {
+            frames.insert(0, format!("{}:{} - {}", filename, frame.line, frame.name));
+        } else {
+            frames.insert(0, format!("{} - {}", filename, frame.name));
+        }
+    }
+    frames.join(";")
+}
+
+fn serialize_python_stacks(traces: &[StackTrace]) -> Vec<u8> {
+    let mut out = Vec::new();
+    push_u32(&mut out, traces.len() as u32);
+    for trace in traces {
+        let stack = format_stack(trace);
+        let stack_bytes = stack.as_bytes();
+        push_u32(&mut out, trace.thread_id as u32);
+        out.push(trace.active as u8);
+        out.push(trace.owns_gil as u8);
+        push_u32(&mut out, trace.os_thread_id as u32);
+        push_u32(&mut out, stack_bytes.len() as u32);
+        out.extend_from_slice(stack_bytes);
+    }
+    out
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_trace::Frame;
+
+    fn sample_trace(thread_id: u64, active: bool) -> StackTrace {
+        StackTrace {
+            pid: 1234,
+            thread_id,
+            thread_name: None,
+            os_thread_id: thread_id,
+            active,
+            owns_gil: active,
+            frames: vec![Frame {
+                name: "foo".to_owned(),
+                filename: "foo.py".to_owned(),
+                short_filename: None,
+                module: None,
+                line: 42,
+            }],
+        }
+    }
+
+    #[test]
+    fn write_produces_a_well_formed_header_and_directory() {
+        let traces = vec![sample_trace(1, true), sample_trace(2, false)];
+        let dump = write(&traces);
+
+        assert_eq!(&dump[0..4], &MINIDUMP_SIGNATURE.to_le_bytes());
+        assert_eq!(&dump[4..8], &MINIDUMP_VERSION.to_le_bytes());
+
+        let stream_count = u32::from_le_bytes(dump[8..12].try_into().unwrap());
+        assert_eq!(stream_count, 2);
+
+        let directory_rva = u32::from_le_bytes(dump[12..16].try_into().unwrap());
+        assert!((directory_rva as usize) < dump.len());
+
+        let thread_list_entry_offset = directory_rva as usize;
+        let thread_list_stream_type = u32::from_le_bytes(
+            dump[thread_list_entry_offset..thread_list_entry_offset + 4]
+                .try_into()
+                .unwrap(),
+        );
+        assert_eq!(thread_list_stream_type, STREAM_THREAD_LIST);
+
+        let thread_list_data_size = u32::from_le_bytes(
+            dump[thread_list_entry_offset + 4..thread_list_entry_offset + 8]
+                .try_into()
+                .unwrap(),
+        );
+        let thread_list_rva = u32::from_le_bytes(
+            dump[thread_list_entry_offset + 8..thread_list_entry_offset + 12]
+                .try_into()
+                .unwrap(),
+        );
+        // The directory's own stream must stay fully within the buffer.
+        assert!((thread_list_rva + thread_list_data_size) as usize <= dump.len());
+
+        let thread_count = u32::from_le_bytes(
+            dump[thread_list_rva as usize..thread_list_rva as usize + 4]
+                .try_into()
+                .unwrap(),
+        );
+        assert_eq!(thread_count, traces.len() as u32);
+        assert_eq!(
+            thread_list_data_size,
+            4 + THREAD_ENTRY_SIZE * traces.len() as u32
+                + traces.len() as u32 * THREAD_CONTEXT_SIZE
+                + traces
+                    .iter()
+                    .map(|t| format_stack(t).len() as u32)
+                    .sum::<u32>()
+        );
+    }
+
+    #[test]
+    fn write_handles_no_threads() {
+        let dump = write(&[]);
+        assert_eq!(&dump[0..4], &MINIDUMP_SIGNATURE.to_le_bytes());
+    }
+}